@@ -11,6 +11,24 @@ pub const MAX_BLOCK_SIGOPS: usize = MAX_BLOCK_SIZE/50; // 40000
 pub const MIN_COINBASE_SIZE: usize = 2;
 pub const MAX_COINBASE_SIZE: usize = 100;
 
+// Below this value, a transaction's nLockTime is interpreted as a block
+// height; at or above it, as a unix timestamp.
+pub const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+// nSequence value marking an input (and, if every input carries it, the
+// transaction itself) as final regardless of nLockTime.
+pub const SEQUENCE_FINAL: u32 = 0xffffffff;
+
+// BIP141 block weight, used in place of MAX_BLOCK_SIZE once segwit is active
+pub const MAX_BLOCK_WEIGHT: usize = 4_000_000;
+
+// Mainnet activated segwit at block 481,824 (BIP141 lock-in + grace period).
+pub const MAINNET_SEGWIT_ACTIVATION_HEIGHT: u32 = 481_824;
+
+// Mainnet activated BIP16 (P2SH) at block 173,805, about 300k blocks before
+// segwit. The two are unrelated soft forks and must not share an activation
+// height.
+pub const MAINNET_BIP16_ACTIVATION_HEIGHT: u32 = 173_805;
+
 pub const RETARGETING_FACTOR: u32 = 4;
 pub const TARGET_SPACING_SECONDS: u32 = 10 * 60;
 pub const DOUBLE_SPACING_SECONDS: u32 = 2 * TARGET_SPACING_SECONDS;
@@ -22,3 +40,65 @@ pub const MAX_TIMESPAN: u32 = TARGET_TIMESPAN_SECONDS * RETARGETING_FACTOR;
 
 // Target number of blocks, 2 weaks, 2016
 pub const RETARGETING_INTERVAL: u32 = TARGET_TIMESPAN_SECONDS / TARGET_SPACING_SECONDS;
+
+/// Which chain a `ConsensusParams` describes. Some consensus rules are tied
+/// to the network itself rather than to any height - e.g. the BIP30
+/// duplicate-coinbase exceptions only ever happened on mainnet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Network {
+	Mainnet,
+	Testnet,
+	Regtest,
+}
+
+/// Network-specific consensus limits, so the same BlockVerifier can serve
+/// mainnet, testnet and regtest instead of hardwiring the mainnet constants.
+///
+/// Segwit is carried as an activation height, not a flag: it is a soft fork,
+/// so whether it is "active" depends on the height being verified, not on
+/// the network alone. A fixed `true`/`false` would be wrong for every block
+/// verified before (or, on a network without segwit, after) that height.
+#[derive(Debug, Clone, Copy)]
+pub struct ConsensusParams {
+	network: Network,
+	max_block_size: usize,
+	bip16_activation_height: u32,
+	segwit_activation_height: u32,
+}
+
+impl ConsensusParams {
+	pub fn new(network: Network, max_block_size: usize, bip16_activation_height: u32, segwit_activation_height: u32) -> Self {
+		ConsensusParams {
+			network: network,
+			max_block_size: max_block_size,
+			bip16_activation_height: bip16_activation_height,
+			segwit_activation_height: segwit_activation_height,
+		}
+	}
+
+	/// Mainnet defaults: 2Mb blocks, BIP16 activated at height 173,805,
+	/// segwit activated at height 481,824.
+	pub fn mainnet() -> Self {
+		ConsensusParams::new(Network::Mainnet, MAX_BLOCK_SIZE, MAINNET_BIP16_ACTIVATION_HEIGHT, MAINNET_SEGWIT_ACTIVATION_HEIGHT)
+	}
+
+	pub fn network(&self) -> Network {
+		self.network
+	}
+
+	pub fn max_block_size(&self) -> usize {
+		self.max_block_size
+	}
+
+	pub fn max_block_sigops(&self) -> usize {
+		self.max_block_size / 50
+	}
+
+	pub fn bip16_active(&self, height: u32) -> bool {
+		height >= self.bip16_activation_height
+	}
+
+	pub fn segwit_active(&self, height: u32) -> bool {
+		height >= self.segwit_activation_height
+	}
+}